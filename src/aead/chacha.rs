@@ -0,0 +1,381 @@
+// Copyright 2015-2016 Brian Smith.
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHORS DISCLAIM ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY
+// SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION
+// OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN
+// CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+use super::{Counter, Iv};
+use crate::{error, polyfill, polyfill::convert::*};
+
+/// The length of a ChaCha20 key in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// The length of a ChaCha20 nonce in bytes, as used by `Key::keystream` and
+/// `Key::keystream_xor`.
+pub const NONCE_LEN: usize = 12;
+
+/// The number of 64-byte blocks that can be addressed by the 32-bit ChaCha20
+/// block counter.
+const MAX_BLOCKS: u64 = 1 << 32;
+
+const KEY_LEN_IN_WORDS: usize = KEY_LEN / 4;
+const BLOCK_LEN_IN_WORDS: usize = 16;
+
+/// A ChaCha20 key held in its expanded, word-oriented form.
+#[derive(Clone)]
+pub struct Key([u32; KEY_LEN_IN_WORDS]);
+
+impl From<&[u8; KEY_LEN]> for Key {
+    fn from(value: &[u8; KEY_LEN]) -> Self {
+        let mut words = [0u32; KEY_LEN_IN_WORDS];
+        for (word, chunk) in words.iter_mut().zip(value.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into_().unwrap());
+        }
+        Key(words)
+    }
+}
+
+impl Key {
+    fn words(&self) -> &[u32; KEY_LEN_IN_WORDS] { &self.0 }
+
+    /// Writes the raw ChaCha20 keystream for `nonce`, starting at block
+    /// `initial_counter`, into `out`.
+    ///
+    /// This is the bare IETF ChaCha20 stream cipher primitive (as used by,
+    /// e.g., `rand_chacha` and other downstream crypto libraries), seekable
+    /// to an arbitrary 32-bit block offset. It is not an AEAD by itself and
+    /// provides no authentication; callers that need authenticated
+    /// encryption should use `CHACHA20_POLY1305` instead.
+    ///
+    /// Returns `error::Unspecified` if `initial_counter` plus the number of
+    /// blocks needed to fill `out` would overflow the 32-bit block counter.
+    pub fn keystream(
+        &self, nonce: &[u8; NONCE_LEN], initial_counter: u32, out: &mut [u8],
+    ) -> Result<(), error::Unspecified> {
+        for byte in out.iter_mut() {
+            *byte = 0;
+        }
+        self.keystream_xor(nonce, initial_counter, out)
+    }
+
+    /// XORs `in_out` in place with the raw ChaCha20 keystream for `nonce`,
+    /// starting at block `initial_counter`.
+    ///
+    /// See `keystream` for the primitive this exposes and the overflow
+    /// behavior enforced here.
+    pub fn keystream_xor(
+        &self, nonce: &[u8; NONCE_LEN], initial_counter: u32, in_out: &mut [u8],
+    ) -> Result<(), error::Unspecified> {
+        check_block_count(initial_counter, polyfill::u64_from_usize(in_out.len()))?;
+
+        let mut counter = initial_counter;
+        let mut block_start = 0;
+        while block_start < in_out.len() {
+            let block_end = core::cmp::min(block_start + 64, in_out.len());
+            let keystream = keystream_block(self.words(), counter, nonce);
+            for (o, k) in in_out[block_start..block_end].iter_mut().zip(keystream.iter()) {
+                *o ^= k;
+            }
+            counter = counter.wrapping_add(1);
+            block_start = block_end;
+        }
+        Ok(())
+    }
+}
+
+/// Ensures that sealing `len` bytes does not wrap the 32-bit ChaCha20 block
+/// counter. The AEAD always consumes block 0 deriving the Poly1305 key, so
+/// the data itself starts at block 1; this must be checked before any
+/// plaintext is transformed so that a rejected call never emits a partial,
+/// counter-reused keystream.
+///
+/// `len` is taken as `u64` rather than `usize` so that callers summing the
+/// lengths of several fragments (which can legitimately exceed `usize::MAX`
+/// on 32-bit targets) widen before adding, rather than risking the sum
+/// itself wrapping and defeating this check.
+pub(super) fn check_seal_block_count(len: u64) -> Result<(), error::Unspecified> {
+    check_block_count(1, len)
+}
+
+/// Ensures that generating `len` bytes of keystream starting at block
+/// `initial_counter` never needs to address a block beyond the 32-bit
+/// block-counter space.
+fn check_block_count(initial_counter: u32, len: u64) -> Result<(), error::Unspecified> {
+    let blocks_needed = len.checked_add(63).map(|n| n / 64).ok_or(error::Unspecified)?;
+    u64::from(initial_counter)
+        .checked_add(blocks_needed)
+        .filter(|&end| end <= MAX_BLOCKS)
+        .ok_or(error::Unspecified)?;
+    Ok(())
+}
+
+/// Either a running block counter (for the AEAD's bulk encryption) or a bare
+/// IV (for one-off uses, such as deriving the Poly1305 key).
+pub(super) enum CounterOrIv {
+    Counter(Counter),
+    Iv(Iv),
+}
+
+impl From<Counter> for CounterOrIv {
+    fn from(counter: Counter) -> Self { CounterOrIv::Counter(counter) }
+}
+
+/// XORs the ChaCha20 keystream, starting at the block indicated by `ctr`,
+/// into `in_out` in place.
+pub(super) fn chacha20_xor_in_place(key: &Key, ctr: CounterOrIv, in_out: &mut [u8]) {
+    chacha20_xor_overlapping(key, ctr, in_out, 0)
+}
+
+/// Like `chacha20_xor_in_place`, but supports shifting the plaintext left by
+/// `in_prefix_len` bytes as it is read, for in-place opening of sealed data.
+pub(super) fn chacha20_xor_overlapping(
+    key: &Key, ctr: impl Into<CounterOrIv>, in_out: &mut [u8], in_prefix_len: usize,
+) {
+    let (initial_counter, nonce) = match ctr.into() {
+        CounterOrIv::Counter(mut counter) => block_state_from_iv(counter.increment()),
+        CounterOrIv::Iv(iv) => block_state_from_iv(iv),
+    };
+
+    let in_out_len = in_out.len() - in_prefix_len;
+    let mut counter = initial_counter;
+    let mut block_start = 0;
+    let mut input = [0u8; 64];
+    while block_start < in_out_len {
+        let block_end = core::cmp::min(block_start + 64, in_out_len);
+        let block_len = block_end - block_start;
+        let keystream = keystream_block(key.words(), counter, &nonce);
+        input[..block_len].copy_from_slice(
+            &in_out[(in_prefix_len + block_start)..(in_prefix_len + block_end)],
+        );
+        xor_block(&mut in_out[block_start..block_end], &input[..block_len], &keystream);
+        counter = counter.wrapping_add(1);
+        block_start = block_end;
+    }
+}
+
+/// A resumable ChaCha20 keystream cursor, for XORing a logical byte stream
+/// that arrives as a sequence of fragments rather than one contiguous
+/// buffer. Unlike `chacha20_xor_in_place`, a `Cursor` can be XORed into
+/// repeatedly, carrying the partial keystream block and its within-block
+/// offset from one fragment to the next so the block counter stays
+/// continuous across the whole stream.
+pub(super) struct Cursor<'k> {
+    key: &'k Key,
+    nonce: [u8; 12],
+    next_counter: u32,
+    block: [u8; 64],
+    // Number of leading bytes of `block` already consumed; 64 means the
+    // block is exhausted and a fresh one must be generated.
+    block_used: usize,
+}
+
+impl<'k> Cursor<'k> {
+    pub(super) fn new(key: &'k Key, ctr: CounterOrIv) -> Self {
+        let (next_counter, nonce) = match ctr {
+            CounterOrIv::Counter(mut counter) => block_state_from_iv(counter.increment()),
+            CounterOrIv::Iv(iv) => block_state_from_iv(iv),
+        };
+        Self {
+            key,
+            nonce,
+            next_counter,
+            block: [0u8; 64],
+            block_used: 64,
+        }
+    }
+
+    /// XORs the next `buf.len()` bytes of keystream into `buf` in place.
+    pub(super) fn xor_in_place(&mut self, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.block_used == 64 {
+                self.block = keystream_block(self.key.words(), self.next_counter, &self.nonce);
+                self.next_counter = self.next_counter.wrapping_add(1);
+                self.block_used = 0;
+            }
+            let available = 64 - self.block_used;
+            let take = core::cmp::min(available, buf.len() - done);
+            for (o, k) in buf[done..(done + take)]
+                .iter_mut()
+                .zip(self.block[self.block_used..(self.block_used + take)].iter())
+            {
+                *o ^= k;
+            }
+            self.block_used += take;
+            done += take;
+        }
+    }
+}
+
+fn block_state_from_iv(iv: Iv) -> (u32, [u8; 12]) {
+    let bytes = iv.into_bytes_less_safe();
+    let mut counter_bytes = [0u8; 4];
+    counter_bytes.copy_from_slice(&bytes[0..4]);
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&bytes[4..16]);
+    (u32::from_le_bytes(counter_bytes), nonce)
+}
+
+fn xor_block(out: &mut [u8], input: &[u8], keystream: &[u8; 64]) {
+    for ((o, i), k) in out.iter_mut().zip(input.iter()).zip(keystream.iter()) {
+        *o = i ^ k;
+    }
+}
+
+/// Computes one 64-byte ChaCha20 keystream block for `counter`/`nonce`.
+fn keystream_block(key_words: &[u32; KEY_LEN_IN_WORDS], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = initial_state(key_words, counter, nonce);
+    let initial = state;
+    for _ in 0..10 {
+        double_round(&mut state);
+    }
+    for (word, initial_word) in state.iter_mut().zip(initial.iter()) {
+        *word = word.wrapping_add(*initial_word);
+    }
+    let mut out = [0u8; 64];
+    for (chunk, word) in out.chunks_exact_mut(4).zip(state.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn initial_state(
+    key_words: &[u32; KEY_LEN_IN_WORDS], counter: u32, nonce: &[u8; 12],
+) -> [u32; BLOCK_LEN_IN_WORDS] {
+    let mut state = [0u32; BLOCK_LEN_IN_WORDS];
+    state[0..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key_words);
+    state[12] = counter;
+    for (word, chunk) in state[13..16].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into_().unwrap());
+    }
+    state
+}
+
+#[inline(always)]
+fn quarter_round(state: &mut [u32; BLOCK_LEN_IN_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(16);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(12);
+
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(8);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(7);
+}
+
+/// Derives the 256-bit HChaCha20 subkey for the first 16 bytes of an
+/// extended (XChaCha20) nonce, as used by `draft-irtf-cfrg-xchacha` and
+/// FreeBSD's `[X]ChaCha20-Poly1305` construction.
+///
+/// Unlike the ChaCha20 block function, HChaCha20 does not add the initial
+/// state back into the permuted state; the subkey is the concatenation of
+/// words 0..4 and 12..16 of the permuted state.
+pub(super) fn hchacha20(key: &Key, nonce: &[u8; 16]) -> Key {
+    let mut state = [0u32; BLOCK_LEN_IN_WORDS];
+    state[0..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key.words());
+    for (word, chunk) in state[12..16].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into_().unwrap());
+    }
+
+    for _ in 0..10 {
+        double_round(&mut state);
+    }
+
+    let mut subkey = [0u32; KEY_LEN_IN_WORDS];
+    subkey[0..4].copy_from_slice(&state[0..4]);
+    subkey[4..8].copy_from_slice(&state[12..16]);
+    Key(subkey)
+}
+
+#[inline(always)]
+fn double_round(state: &mut [u32; BLOCK_LEN_IN_WORDS]) {
+    // Column round.
+    quarter_round(state, 0, 4, 8, 12);
+    quarter_round(state, 1, 5, 9, 13);
+    quarter_round(state, 2, 6, 10, 14);
+    quarter_round(state, 3, 7, 11, 15);
+    // Diagonal round.
+    quarter_round(state, 0, 5, 10, 15);
+    quarter_round(state, 1, 6, 11, 12);
+    quarter_round(state, 2, 7, 8, 13);
+    quarter_round(state, 3, 4, 9, 14);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hchacha20_known_answer_test() {
+        // draft-irtf-cfrg-xchacha-03 §2.2.1.
+        let key = Key::from(&[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ]);
+        let nonce: [u8; 16] = [
+            0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x4a, 0x00, 0x00, 0x00, 0x00, 0x31, 0x41,
+            0x59, 0x27,
+        ];
+        let expected: [u8; 32] = [
+            0x82, 0x41, 0x3b, 0x42, 0x27, 0xb2, 0x7b, 0xfe, 0xd3, 0x0e, 0x42, 0x50, 0x8a, 0x87,
+            0x7d, 0x73, 0xa0, 0xf9, 0xe4, 0xd5, 0x8a, 0x74, 0xa8, 0x53, 0xc1, 0x2e, 0xc4, 0x13,
+            0x26, 0xd3, 0xec, 0xdc,
+        ];
+
+        let subkey = hchacha20(&key, &nonce);
+        let mut actual = [0u8; 32];
+        for (chunk, word) in actual.chunks_exact_mut(4).zip(subkey.0.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn keystream_seek_matches_sequential_generation() {
+        let key = Key::from(&[0x5au8; 32]);
+        let nonce = [0x7bu8; NONCE_LEN];
+
+        let mut whole = [0u8; 128];
+        key.keystream(&nonce, 0, &mut whole).unwrap();
+
+        let mut second_block = [0u8; 64];
+        key.keystream(&nonce, 1, &mut second_block).unwrap();
+
+        assert_eq!(&whole[64..], &second_block[..]);
+    }
+
+    #[test]
+    fn keystream_rejects_counter_overflow() {
+        let key = Key::from(&[0x5au8; 32]);
+        let nonce = [0x7bu8; NONCE_LEN];
+
+        // `u32::MAX` is the last addressable block; two blocks' worth of
+        // output would need to go past it.
+        let mut buf = [0u8; 128];
+        assert!(key.keystream(&nonce, u32::MAX, &mut buf).is_err());
+
+        // Exactly the last block is still fine.
+        let mut last_block = [0u8; 64];
+        assert!(key.keystream(&nonce, u32::MAX, &mut last_block).is_ok());
+    }
+}
@@ -30,10 +30,30 @@ pub static CHACHA20_POLY1305: aead::Algorithm = aead::Algorithm {
     init: chacha20_poly1305_init,
     seal: chacha20_poly1305_seal,
     open: chacha20_poly1305_open,
+    seal_fragmented: chacha20_poly1305_seal_fragmented,
+    open_fragmented: chacha20_poly1305_open_fragmented,
     id: aead::AlgorithmID::CHACHA20_POLY1305,
     max_input_len: super::max_input_len(64, 1),
 };
 
+/// XChaCha20-Poly1305, using HChaCha20 to extend the nonce space to 192
+/// bits.
+///
+/// The keys are 256 bits long and the nonces are 192 bits long. Unlike the
+/// 96-bit nonces of `CHACHA20_POLY1305`, the extended nonce is large enough
+/// to be chosen at random without a meaningful risk of reuse, which makes
+/// this algorithm a better fit for large files and long-lived keys.
+pub static XCHACHA20_POLY1305: aead::Algorithm = aead::Algorithm {
+    key_len: chacha::KEY_LEN,
+    init: chacha20_poly1305_init,
+    seal: xchacha20_poly1305_seal,
+    open: xchacha20_poly1305_open,
+    seal_fragmented: xchacha20_poly1305_seal_fragmented,
+    open_fragmented: xchacha20_poly1305_open_fragmented,
+    id: aead::AlgorithmID::XCHACHA20_POLY1305,
+    max_input_len: super::max_input_len(64, 1),
+};
+
 /// Copies |key| into |ctx_buf|.
 fn chacha20_poly1305_init(key: &[u8]) -> Result<aead::KeyInner, error::Unspecified> {
     let key: &[u8; chacha::KEY_LEN] = key.try_into_()?;
@@ -43,19 +63,74 @@ fn chacha20_poly1305_init(key: &[u8]) -> Result<aead::KeyInner, error::Unspecifi
 fn chacha20_poly1305_seal(
     key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [u8],
 ) -> Result<Tag, error::Unspecified> {
-    Ok(aead(key, nonce, ad, in_out, Direction::Sealing))
+    aead(key, nonce, ad, in_out, Direction::Sealing)
 }
 
 fn chacha20_poly1305_open(
     key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_prefix_len: usize, in_out: &mut [u8],
 ) -> Result<Tag, error::Unspecified> {
-    Ok(aead(
+    aead(
         key,
         nonce,
         ad,
         in_out,
         Direction::Opening { in_prefix_len },
-    ))
+    )
+}
+
+fn xchacha20_poly1305_seal(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [u8],
+) -> Result<Tag, error::Unspecified> {
+    let (subkey, ietf_nonce) = xchacha20_subkey_and_nonce(key, nonce)?;
+    aead(&subkey, ietf_nonce, ad, in_out, Direction::Sealing)
+}
+
+fn xchacha20_poly1305_open(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_prefix_len: usize, in_out: &mut [u8],
+) -> Result<Tag, error::Unspecified> {
+    let (subkey, ietf_nonce) = xchacha20_subkey_and_nonce(key, nonce)?;
+    aead(
+        &subkey,
+        ietf_nonce,
+        ad,
+        in_out,
+        Direction::Opening { in_prefix_len },
+    )
+}
+
+fn xchacha20_poly1305_seal_fragmented(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [&mut [u8]],
+) -> Result<Tag, error::Unspecified> {
+    let (subkey, ietf_nonce) = xchacha20_subkey_and_nonce(key, nonce)?;
+    chacha20_poly1305_seal_fragmented(&subkey, ietf_nonce, ad, in_out)
+}
+
+fn xchacha20_poly1305_open_fragmented(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [&mut [u8]],
+) -> Result<Tag, error::Unspecified> {
+    let (subkey, ietf_nonce) = xchacha20_subkey_and_nonce(key, nonce)?;
+    chacha20_poly1305_open_fragmented(&subkey, ietf_nonce, ad, in_out)
+}
+
+/// Derives the HChaCha20 subkey and the 96-bit IETF nonce
+/// `0x00000000 || nonce[16..24]` from a 192-bit XChaCha20 nonce.
+fn xchacha20_subkey_and_nonce(
+    key: &aead::KeyInner, nonce: NonceRef,
+) -> Result<(aead::KeyInner, NonceRef), error::Unspecified> {
+    let chacha20_key = match key {
+        aead::KeyInner::ChaCha20Poly1305(key) => key,
+        _ => unreachable!(),
+    };
+
+    let nonce: &[u8; 24] = nonce.as_ref().try_into_()?;
+    let hchacha20_nonce: &[u8; 16] = nonce[..16].try_into_()?;
+    let subkey = chacha::hchacha20(chacha20_key, hchacha20_nonce);
+
+    let mut ietf_nonce_bytes = [0u8; 12];
+    ietf_nonce_bytes[4..12].copy_from_slice(&nonce[16..24]);
+    let ietf_nonce = NonceRef::assume_unique_for_key(&ietf_nonce_bytes);
+
+    Ok((aead::KeyInner::ChaCha20Poly1305(subkey), ietf_nonce))
 }
 
 pub type Key = chacha::Key;
@@ -63,12 +138,18 @@ pub type Key = chacha::Key;
 #[inline(always)] // Statically eliminate branches on `direction`.
 fn aead(
     key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [u8], direction: Direction,
-) -> Tag {
+) -> Result<Tag, error::Unspecified> {
     let chacha20_key = match key {
         aead::KeyInner::ChaCha20Poly1305(key) => key,
         _ => unreachable!(),
     };
 
+    // Fail closed, before any plaintext is transformed, rather than let the
+    // 32-bit ChaCha20 block counter silently wrap and reuse keystream.
+    if let Direction::Sealing = direction {
+        chacha::check_seal_block_count(polyfill::u64_from_usize(in_out.len()))?;
+    }
+
     let mut counter = Counter::zero(nonce);
     let mut ctx = {
         let key = derive_poly1305_key(chacha20_key, counter.increment());
@@ -101,7 +182,7 @@ fn aead(
         ),
         poly1305::Pad::Pad,
     );
-    ctx.finish()
+    Ok(ctx.finish())
 }
 
 #[inline]
@@ -118,6 +199,151 @@ fn poly1305_update_padded_16(ctx: &mut poly1305::Context, input: &[u8]) {
     }
 }
 
+/// Like `chacha20_poly1305_seal`, but operates on `in_out`'s fragments in
+/// place rather than requiring them to already be one contiguous buffer.
+/// This lets a caller encrypt a packet assembled from separate memory
+/// regions (e.g. header, payload, trailer) without first copying them into
+/// a single allocation. Reachable from outside this module via
+/// `Algorithm::seal_fragmented`, the same way `chacha20_poly1305_seal` is
+/// reached via `Algorithm::seal`.
+fn chacha20_poly1305_seal_fragmented(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [&mut [u8]],
+) -> Result<Tag, error::Unspecified> {
+    let chacha20_key = match key {
+        aead::KeyInner::ChaCha20Poly1305(key) => key,
+        _ => unreachable!(),
+    };
+
+    // As with the contiguous seal path, check for block-counter overflow
+    // before transforming any fragment's plaintext. Each fragment's length
+    // is widened to `u64` before summing so the total can't wrap `usize`
+    // (and thus defeat this check) on 32-bit targets.
+    let total_len: u64 = in_out
+        .iter()
+        .map(|fragment| polyfill::u64_from_usize(fragment.len()))
+        .sum();
+    chacha::check_seal_block_count(total_len)?;
+
+    let mut counter = Counter::zero(nonce);
+    let mut ctx = {
+        let key = derive_poly1305_key(chacha20_key, counter.increment());
+        poly1305::Context::from_key(key)
+    };
+    poly1305_update_padded_16(&mut ctx, ad);
+
+    let mut cursor = chacha::Cursor::new(chacha20_key, chacha::CounterOrIv::Counter(counter));
+    let mut padder = FragmentedPoly1305::new(&mut ctx);
+    let mut in_out_len = 0;
+    for fragment in in_out.iter_mut() {
+        cursor.xor_in_place(fragment);
+        padder.update(fragment);
+        in_out_len += fragment.len();
+    }
+    padder.finish();
+
+    ctx.update_block(
+        Block::from_u64_le(
+            LittleEndian::from(polyfill::u64_from_usize(ad.len())),
+            LittleEndian::from(polyfill::u64_from_usize(in_out_len)),
+        ),
+        poly1305::Pad::Pad,
+    );
+    Ok(ctx.finish())
+}
+
+/// Like `chacha20_poly1305_open`, but operates on `in_out`'s fragments in
+/// place rather than requiring them to already be one contiguous buffer.
+/// Reachable from outside this module via `Algorithm::open_fragmented`.
+fn chacha20_poly1305_open_fragmented(
+    key: &aead::KeyInner, nonce: NonceRef, ad: &[u8], in_out: &mut [&mut [u8]],
+) -> Result<Tag, error::Unspecified> {
+    let chacha20_key = match key {
+        aead::KeyInner::ChaCha20Poly1305(key) => key,
+        _ => unreachable!(),
+    };
+
+    let mut counter = Counter::zero(nonce);
+    let mut ctx = {
+        let key = derive_poly1305_key(chacha20_key, counter.increment());
+        poly1305::Context::from_key(key)
+    };
+    poly1305_update_padded_16(&mut ctx, ad);
+
+    let mut cursor = chacha::Cursor::new(chacha20_key, chacha::CounterOrIv::Counter(counter));
+    let mut padder = FragmentedPoly1305::new(&mut ctx);
+    let mut in_out_len = 0;
+    for fragment in in_out.iter_mut() {
+        padder.update(fragment);
+        cursor.xor_in_place(fragment);
+        in_out_len += fragment.len();
+    }
+    padder.finish();
+
+    ctx.update_block(
+        Block::from_u64_le(
+            LittleEndian::from(polyfill::u64_from_usize(ad.len())),
+            LittleEndian::from(polyfill::u64_from_usize(in_out_len)),
+        ),
+        poly1305::Pad::Pad,
+    );
+    Ok(ctx.finish())
+}
+
+/// Feeds a byte stream into a `poly1305::Context` 16 bytes at a time, even
+/// when it arrives split across multiple `update` calls, carrying the
+/// not-yet-processed remainder between calls. `finish` applies the single
+/// zero-pad for the final partial block, matching `poly1305_update_padded_16`
+/// applied to the concatenation of every `update`d slice.
+struct FragmentedPoly1305<'c> {
+    ctx: &'c mut poly1305::Context,
+    pending: [u8; BLOCK_LEN],
+    pending_len: usize,
+}
+
+impl<'c> FragmentedPoly1305<'c> {
+    fn new(ctx: &'c mut poly1305::Context) -> Self {
+        Self {
+            ctx,
+            pending: [0u8; BLOCK_LEN],
+            pending_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        if self.pending_len > 0 {
+            let needed = BLOCK_LEN - self.pending_len;
+            let take = core::cmp::min(needed, input.len());
+            self.pending[self.pending_len..(self.pending_len + take)]
+                .copy_from_slice(&input[..take]);
+            self.pending_len += take;
+            input = &input[take..];
+            if self.pending_len < BLOCK_LEN {
+                return;
+            }
+            self.ctx.update_blocks(&self.pending);
+            self.pending_len = 0;
+        }
+
+        let remainder_len = input.len() % BLOCK_LEN;
+        let whole_len = input.len() - remainder_len;
+        if whole_len > 0 {
+            self.ctx.update_blocks(&input[..whole_len]);
+        }
+        if remainder_len > 0 {
+            self.pending[..remainder_len].copy_from_slice(&input[whole_len..]);
+            self.pending_len = remainder_len;
+        }
+    }
+
+    fn finish(self) {
+        if self.pending_len > 0 {
+            let mut block = Block::zero();
+            block.partial_copy_from(&self.pending[..self.pending_len]);
+            self.ctx.update_block(block, poly1305::Pad::Pad);
+        }
+    }
+}
+
 // Also used by chacha20_poly1305_openssh.
 pub(super) fn derive_poly1305_key(chacha_key: &chacha::Key, iv: Iv) -> poly1305::Key {
     let mut blocks = [Block::zero(); poly1305::KEY_BLOCKS];
@@ -136,4 +362,187 @@ mod tests {
         // Errata 4858 at https://www.rfc-editor.org/errata_search.php?rfc=7539.
         assert_eq!(super::CHACHA20_POLY1305.max_input_len, 274_877_906_880u64);
     }
+
+    #[test]
+    fn xchacha20_poly1305_max_input_len_test() {
+        // The limit is derived the same way as CHACHA20_POLY1305's; only the
+        // nonce is extended, not the block counter.
+        assert_eq!(super::XCHACHA20_POLY1305.max_input_len, 274_877_906_880u64);
+    }
+
+    #[test]
+    fn xchacha20_poly1305_seal_open_round_trip() {
+        let key_bytes = [0x11u8; 32];
+        let nonce_bytes = [0x22u8; 24];
+        let ad = b"xchacha additional data";
+        let plaintext =
+            b"xchacha roundtrip plaintext, long enough to span a block and then some more.";
+
+        let key = super::chacha20_poly1305_init(&key_bytes).unwrap();
+
+        let mut sealed = plaintext.to_vec();
+        let seal_tag = super::xchacha20_poly1305_seal(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            &mut sealed,
+        )
+        .unwrap();
+        assert_ne!(sealed, plaintext);
+
+        let mut opened = sealed;
+        let open_tag = super::xchacha20_poly1305_open(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            0,
+            &mut opened,
+        )
+        .unwrap();
+
+        assert_eq!(opened, plaintext);
+        assert_eq!(seal_tag.as_ref(), open_tag.as_ref());
+    }
+
+    #[test]
+    fn seal_fragmented_matches_contiguous_at_unaligned_boundaries() {
+        let key_bytes = [0x33u8; 32];
+        let nonce_bytes = [0x44u8; 12];
+        let ad = b"fragmented additional data";
+        let plaintext =
+            b"The quick brown fox jumps over the lazy dog, past a 16-byte block boundary.";
+
+        let key = super::chacha20_poly1305_init(&key_bytes).unwrap();
+
+        let mut contiguous = plaintext.to_vec();
+        let contiguous_tag = super::chacha20_poly1305_seal(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            &mut contiguous,
+        )
+        .unwrap();
+
+        // Split at boundaries that are not multiples of BLOCK_LEN (16).
+        let (first, rest) = plaintext.split_at(5);
+        let (second, third) = rest.split_at(37);
+        let mut first = first.to_vec();
+        let mut second = second.to_vec();
+        let mut third = third.to_vec();
+        let fragmented_tag = super::chacha20_poly1305_seal_fragmented(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            &mut [&mut first, &mut second, &mut third],
+        )
+        .unwrap();
+
+        let mut fragmented = Vec::new();
+        fragmented.extend_from_slice(&first);
+        fragmented.extend_from_slice(&second);
+        fragmented.extend_from_slice(&third);
+
+        assert_eq!(contiguous, fragmented);
+        assert_eq!(contiguous_tag.as_ref(), fragmented_tag.as_ref());
+
+        // And the fragmented ciphertext opens, via the contiguous path, back
+        // to the original plaintext.
+        let mut opened = fragmented;
+        let open_tag = super::chacha20_poly1305_open(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            0,
+            &mut opened,
+        )
+        .unwrap();
+        assert_eq!(&opened, plaintext);
+        assert_eq!(open_tag.as_ref(), contiguous_tag.as_ref());
+    }
+
+    #[test]
+    fn open_fragmented_matches_contiguous_at_unaligned_boundaries() {
+        let key_bytes = [0x55u8; 32];
+        let nonce_bytes = [0x66u8; 12];
+        let ad = b"more additional data";
+        let plaintext = b"Split open() across fragments that don't land on 16-byte boundaries either.";
+
+        let key = super::chacha20_poly1305_init(&key_bytes).unwrap();
+
+        let mut sealed = plaintext.to_vec();
+        let seal_tag = super::chacha20_poly1305_seal(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            &mut sealed,
+        )
+        .unwrap();
+
+        // Split at boundaries that are not multiples of BLOCK_LEN (16).
+        let (first, rest) = sealed.split_at(9);
+        let (second, third) = rest.split_at(24);
+        let mut first = first.to_vec();
+        let mut second = second.to_vec();
+        let mut third = third.to_vec();
+        let open_tag = super::chacha20_poly1305_open_fragmented(
+            &key,
+            super::NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            &mut [&mut first, &mut second, &mut third],
+        )
+        .unwrap();
+
+        let mut opened = Vec::new();
+        opened.extend_from_slice(&first);
+        opened.extend_from_slice(&second);
+        opened.extend_from_slice(&third);
+
+        assert_eq!(&opened, plaintext);
+        assert_eq!(open_tag.as_ref(), seal_tag.as_ref());
+    }
+
+    #[test]
+    fn algorithm_fragmented_fields_round_trip() {
+        // Exercise the fragmented entry points the way a real caller would:
+        // through `Algorithm::seal_fragmented`/`open_fragmented`, for both
+        // algorithms that wire them up.
+        for algorithm in [&super::CHACHA20_POLY1305, &super::XCHACHA20_POLY1305] {
+            let key_bytes = [0x77u8; 32];
+            let nonce_bytes: Vec<u8> =
+                if algorithm.id == super::aead::AlgorithmID::XCHACHA20_POLY1305 {
+                    vec![0x88u8; 24]
+                } else {
+                    vec![0x88u8; 12]
+                };
+            let plaintext = b"fragmented entry point wired through Algorithm";
+
+            let key = (algorithm.init)(&key_bytes).unwrap();
+
+            let (first, second) = plaintext.split_at(11);
+            let mut first = first.to_vec();
+            let mut second = second.to_vec();
+            let tag = (algorithm.seal_fragmented)(
+                &key,
+                super::NonceRef::assume_unique_for_key(&nonce_bytes),
+                b"ad",
+                &mut [&mut first, &mut second],
+            )
+            .unwrap();
+
+            let open_tag = (algorithm.open_fragmented)(
+                &key,
+                super::NonceRef::assume_unique_for_key(&nonce_bytes),
+                b"ad",
+                &mut [&mut first, &mut second],
+            )
+            .unwrap();
+
+            let mut opened = Vec::new();
+            opened.extend_from_slice(&first);
+            opened.extend_from_slice(&second);
+
+            assert_eq!(&opened, plaintext);
+            assert_eq!(tag.as_ref(), open_tag.as_ref());
+        }
+    }
 }
@@ -0,0 +1,350 @@
+// Copyright 2015-2016 Brian Smith.
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHORS DISCLAIM ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY
+// SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION
+// OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN
+// CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! The STREAM construction (Rogaway and Hoang, "Robust Authenticated
+//! Encryption and the Limits of Symmetric Cryptography") for sealing a
+//! large message as a sequence of fixed-size chunks under a 96-bit-nonce
+//! AEAD like `CHACHA20_POLY1305`, so gigabyte-scale files can be processed
+//! with bounded memory.
+//!
+//! Each chunk's nonce is `nonce_prefix || chunk_counter || last_block_flag`,
+//! where `chunk_counter` is a big-endian counter that increments once per
+//! chunk and `last_block_flag` is `1` only for the final chunk. Binding the
+//! counter and the flag into the nonce this way makes the stream
+//! order-resistant (a reordered chunk is sealed under the wrong nonce and
+//! fails `OpeningKey::open_chunk`'s tag check) and truncation-resistant (a
+//! stream that stops before a chunk with the flag set is rejected by
+//! `OpeningKey::finish`).
+//!
+//! The nonce is always 96 bits, so only `CHACHA20_POLY1305` may be used
+//! here; `SealingKey::new`/`OpeningKey::new` reject `XCHACHA20_POLY1305` and
+//! any other 192-bit-nonce algorithm.
+
+use super::{NonceRef, Tag};
+use crate::{aead, error};
+
+/// The number of bytes of the 96-bit nonce available for the caller-chosen
+/// prefix; the remaining 5 bytes carry the big-endian chunk counter (4
+/// bytes) and the last-chunk flag (1 byte).
+pub const NONCE_PREFIX_LEN: usize = 12 - 4 - 1;
+
+/// Whether a chunk is the last one in the stream. This is bound into the
+/// chunk's nonce, not sent separately, so the AEAD tag implicitly
+/// authenticates it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkFlag {
+    NotLast,
+    Last,
+}
+
+/// `chunk_nonce` always builds a 96-bit nonce, so only algorithms that take
+/// 96-bit nonces (`CHACHA20_POLY1305`) may be used here; `XCHACHA20_POLY1305`
+/// expects a 192-bit nonce and would have every `seal_chunk`/`open_chunk`
+/// call fail confusingly if it were allowed through.
+fn check_96_bit_nonce_algorithm(algorithm: &aead::Algorithm) -> Result<(), error::Unspecified> {
+    match algorithm.id {
+        aead::AlgorithmID::CHACHA20_POLY1305 => Ok(()),
+        _ => Err(error::Unspecified),
+    }
+}
+
+/// Compares two tags in constant time, as is required before trusting any
+/// decrypted output: `(algorithm.open)` only *computes* a tag from the
+/// ciphertext it was given, so without this comparison a reordered,
+/// truncated, or otherwise tampered-with chunk would be accepted as long as
+/// it decrypted without a lower-level error.
+fn verify_tags_equal(computed: &Tag, received: &Tag) -> Result<(), error::Unspecified> {
+    let computed = computed.as_ref();
+    let received = received.as_ref();
+    if computed.len() != received.len() {
+        return Err(error::Unspecified);
+    }
+    let mismatch = computed
+        .iter()
+        .zip(received.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if mismatch == 0 {
+        Ok(())
+    } else {
+        Err(error::Unspecified)
+    }
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, chunk: ChunkFlag) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..(NONCE_PREFIX_LEN + 4)].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = match chunk {
+        ChunkFlag::NotLast => 0,
+        ChunkFlag::Last => 1,
+    };
+    nonce
+}
+
+/// Seals a message chunk-by-chunk using the STREAM nonce construction.
+pub struct SealingKey {
+    algorithm: &'static aead::Algorithm,
+    key: aead::KeyInner,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    closed: bool,
+}
+
+impl SealingKey {
+    pub fn new(
+        algorithm: &'static aead::Algorithm, key_bytes: &[u8],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    ) -> Result<Self, error::Unspecified> {
+        check_96_bit_nonce_algorithm(algorithm)?;
+        let key = (algorithm.init)(key_bytes)?;
+        Ok(Self {
+            algorithm,
+            key,
+            nonce_prefix,
+            counter: 0,
+            closed: false,
+        })
+    }
+
+    /// Seals the next chunk of `in_out` in place, returning its tag. Pass
+    /// `ChunkFlag::Last` for, and only for, the final chunk of the message;
+    /// sealing any chunk after the last one fails with
+    /// `error::Unspecified`.
+    pub fn seal_chunk(
+        &mut self, chunk: ChunkFlag, ad: &[u8], in_out: &mut [u8],
+    ) -> Result<Tag, error::Unspecified> {
+        if self.closed {
+            return Err(error::Unspecified);
+        }
+        let nonce_bytes = chunk_nonce(&self.nonce_prefix, self.counter, chunk);
+        // The counter only needs to advance to seal a *subsequent* chunk, so
+        // don't gate sealing the final chunk on that advance succeeding:
+        // `self.counter == u32::MAX` is a legitimate nonce for the last
+        // chunk even though there's no `u32::MAX + 1` to move on to.
+        let next_counter = match chunk {
+            ChunkFlag::NotLast => Some(self.counter.checked_add(1).ok_or(error::Unspecified)?),
+            ChunkFlag::Last => None,
+        };
+
+        let tag = (self.algorithm.seal)(
+            &self.key,
+            NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            in_out,
+        )?;
+        // Only commit the counter/closed-flag advance once the chunk has
+        // actually been sealed, so a failed call can be retried under the
+        // same nonce it was (not) sent under, rather than silently skipping
+        // ahead and desyncing the stream.
+        if let Some(next_counter) = next_counter {
+            self.counter = next_counter;
+        }
+        if chunk == ChunkFlag::Last {
+            self.closed = true;
+        }
+        Ok(tag)
+    }
+}
+
+/// Opens a message chunk-by-chunk using the STREAM nonce construction,
+/// rejecting reordered chunks (which were sealed under a different nonce
+/// and so fail the tag comparison) and truncated streams (via `finish`).
+pub struct OpeningKey {
+    algorithm: &'static aead::Algorithm,
+    key: aead::KeyInner,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    closed: bool,
+}
+
+impl OpeningKey {
+    pub fn new(
+        algorithm: &'static aead::Algorithm, key_bytes: &[u8],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    ) -> Result<Self, error::Unspecified> {
+        check_96_bit_nonce_algorithm(algorithm)?;
+        let key = (algorithm.init)(key_bytes)?;
+        Ok(Self {
+            algorithm,
+            key,
+            nonce_prefix,
+            counter: 0,
+            closed: false,
+        })
+    }
+
+    /// Opens the next chunk of `in_out` in place, checking it against
+    /// `received_tag`. Chunks must be opened in the order they were sealed;
+    /// a reordered or tampered chunk, or one opened with the wrong
+    /// `ChunkFlag`, fails the tag comparison and returns
+    /// `error::Unspecified` without advancing the stream.
+    pub fn open_chunk(
+        &mut self, chunk: ChunkFlag, ad: &[u8], received_tag: &Tag, in_out: &mut [u8],
+    ) -> Result<(), error::Unspecified> {
+        if self.closed {
+            return Err(error::Unspecified);
+        }
+        let nonce_bytes = chunk_nonce(&self.nonce_prefix, self.counter, chunk);
+        // See the matching comment in `SealingKey::seal_chunk`: only require
+        // the advance to succeed when there's a next chunk that will need
+        // it.
+        let next_counter = match chunk {
+            ChunkFlag::NotLast => Some(self.counter.checked_add(1).ok_or(error::Unspecified)?),
+            ChunkFlag::Last => None,
+        };
+
+        let tag = (self.algorithm.open)(
+            &self.key,
+            NonceRef::assume_unique_for_key(&nonce_bytes),
+            ad,
+            0,
+            in_out,
+        )?;
+        verify_tags_equal(&tag, received_tag)?;
+
+        // Only commit the counter/closed-flag advance once the chunk has
+        // actually verified, so a failed call can be retried under the same
+        // nonce it was (not) sent under, rather than silently skipping
+        // ahead and desyncing the stream.
+        if let Some(next_counter) = next_counter {
+            self.counter = next_counter;
+        }
+        if chunk == ChunkFlag::Last {
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    /// Confirms the stream was not truncated. Must be called once the
+    /// caller believes it has seen the whole message; fails if no chunk
+    /// carrying `ChunkFlag::Last` was ever opened.
+    pub fn finish(self) -> Result<(), error::Unspecified> {
+        if self.closed {
+            Ok(())
+        } else {
+            Err(error::Unspecified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aead::CHACHA20_POLY1305;
+
+    const KEY: [u8; 32] = [0x11u8; 32];
+    const PREFIX: [u8; NONCE_PREFIX_LEN] = [0x22u8; NONCE_PREFIX_LEN];
+
+    fn seal_stream(chunks: &[&[u8]]) -> Vec<(Vec<u8>, Tag)> {
+        let mut sealing = SealingKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let is_last = i == chunks.len() - 1;
+                let flag = if is_last { ChunkFlag::Last } else { ChunkFlag::NotLast };
+                let mut buf = chunk.to_vec();
+                let tag = sealing.seal_chunk(flag, b"", &mut buf).unwrap();
+                (buf, tag)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_in_order() {
+        let chunks: [&[u8]; 3] = [
+            b"first chunk of the stream",
+            b"second chunk, still going",
+            b"final chunk!",
+        ];
+        let sealed = seal_stream(&chunks);
+
+        let mut opening = OpeningKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        for (i, (ciphertext, tag)) in sealed.iter().enumerate() {
+            let is_last = i == sealed.len() - 1;
+            let flag = if is_last { ChunkFlag::Last } else { ChunkFlag::NotLast };
+            let mut buf = ciphertext.clone();
+            opening.open_chunk(flag, b"", tag, &mut buf).unwrap();
+            assert_eq!(buf, chunks[i]);
+        }
+        assert!(opening.finish().is_ok());
+    }
+
+    #[test]
+    fn truncated_stream_is_rejected() {
+        let chunks: [&[u8]; 3] = [b"one", b"two", b"three"];
+        let sealed = seal_stream(&chunks);
+
+        let mut opening = OpeningKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        // Open every chunk except the final one, so `ChunkFlag::Last` is
+        // never seen.
+        for (ciphertext, tag) in sealed.iter().take(sealed.len() - 1) {
+            let mut buf = ciphertext.clone();
+            opening.open_chunk(ChunkFlag::NotLast, b"", tag, &mut buf).unwrap();
+        }
+        assert!(opening.finish().is_err());
+    }
+
+    #[test]
+    fn reordered_chunk_is_rejected() {
+        let chunks: [&[u8]; 2] = [b"chunk zero", b"chunk one"];
+        let sealed = seal_stream(&chunks);
+
+        let mut opening = OpeningKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        // Present chunk 1's ciphertext/tag first, where chunk 0 was
+        // expected; its nonce (bound to counter 1) doesn't match the
+        // counter-0 nonce `open_chunk` will derive, so the tag check fails.
+        let (ciphertext, tag) = &sealed[1];
+        let mut buf = ciphertext.clone();
+        assert!(opening
+            .open_chunk(ChunkFlag::NotLast, b"", tag, &mut buf)
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_chunk_is_rejected() {
+        let chunks: [&[u8]; 1] = [b"only chunk, and it's the last one"];
+        let sealed = seal_stream(&chunks);
+
+        let mut opening = OpeningKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        let (ciphertext, tag) = &sealed[0];
+        let mut buf = ciphertext.clone();
+        buf[0] ^= 0x01;
+        assert!(opening.open_chunk(ChunkFlag::Last, b"", tag, &mut buf).is_err());
+    }
+
+    #[test]
+    fn last_chunk_at_max_counter_is_accepted() {
+        // `u32::MAX` is a legitimate counter value for the *last* chunk: the
+        // advance to a (nonexistent) next chunk must not be required to seal
+        // or open it.
+        let mut sealing = SealingKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        sealing.counter = u32::MAX;
+        let mut buf = b"final chunk at the counter boundary".to_vec();
+        let tag = sealing.seal_chunk(ChunkFlag::Last, b"", &mut buf).unwrap();
+
+        let mut opening = OpeningKey::new(&CHACHA20_POLY1305, &KEY, PREFIX).unwrap();
+        opening.counter = u32::MAX;
+        opening.open_chunk(ChunkFlag::Last, b"", &tag, &mut buf).unwrap();
+        assert!(opening.finish().is_ok());
+    }
+
+    #[test]
+    fn non_96_bit_nonce_algorithm_is_rejected() {
+        use crate::aead::XCHACHA20_POLY1305;
+
+        assert!(SealingKey::new(&XCHACHA20_POLY1305, &KEY, PREFIX).is_err());
+        assert!(OpeningKey::new(&XCHACHA20_POLY1305, &KEY, PREFIX).is_err());
+    }
+}